@@ -11,76 +11,376 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::boxed::{Box, FnBox};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::fmt::{self, Formatter, Display};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use std::thread::{self, JoinHandle};
 
+use futures::{self, Future};
+use futures::sync::oneshot;
 use kvproto::metapb;
 
 use util;
 use util::collections::HashMap;
-use util::worker::{Runnable, Worker};
-use pd::PdClient;
+use util::worker::{Runnable, Scheduler, Worker};
+use pd::{PdClient, PdFuture};
 
 use super::Result;
 use super::metrics::*;
 
 const STORE_ADDRESS_REFRESH_SECONDS: u64 = 60;
 
+// How often the refresh ticker wakes up to check for shutdown while
+// waiting out its tick interval. Keeping this short bounds how long
+// `Drop` can block joining the ticker thread.
+const REFRESH_TICKER_POLL_MILLIS: u64 = 100;
+
+// Backoff applied to a store whose resolution keeps failing, so that a
+// flapping store does not get hammered with a `get_store` call on every
+// single `resolve`.
+const RESOLVE_BACKOFF_BASE_MILLIS: u64 = 500;
+const RESOLVE_BACKOFF_CAP_MILLIS: u64 = 30_000;
+
+// Caps the number of `get_store` calls the resolver is allowed to send to
+// PD in each fixed one-second window, so a cache stampede across many
+// distinct stores is smoothed out instead of flooding PD in one tick.
+const MAX_RESOLVES_PER_SEC: u32 = 20;
+
 pub type Callback = Box<FnBox(Result<SocketAddr>) + Send>;
 
 // StoreAddrResolver resolves the store address.
 pub trait StoreAddrResolver {
     // Resolve resolves the store address asynchronously.
     fn resolve(&self, store_id: u64, cb: Callback) -> Result<()>;
+
+    /// Resolves the store address, returning a future instead of taking a
+    /// callback, so the result can be composed into a `PdFuture` pipeline
+    /// (e.g. chained after `PdClient::get_region_by_id`) without the caller
+    /// writing a callback. `PdStoreAddrResolver` still allocates a boxed
+    /// callback internally and resolves on its own worker thread.
+    fn resolve_future(&self, store_id: u64) -> PdFuture<SocketAddr>;
 }
 
 /// Snapshot generating task.
-struct Task {
-    store_id: u64,
-    cb: Callback,
+enum Task {
+    Resolve { store_id: u64, cb: Callback },
+    /// Periodic tick driving the proactive background refresh: entries
+    /// whose cache is close to expiry are re-resolved ahead of time so
+    /// that `resolve` almost always hits a warm cache.
+    Refresh,
 }
 
 impl Display for Task {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "resolve store {} address", self.store_id)
+        match *self {
+            Task::Resolve { store_id, .. } => write!(f, "resolve store {} address", store_id),
+            Task::Refresh => write!(f, "refresh store addresses"),
+        }
+    }
+}
+
+/// Health state of a cached store address, used to decide whether a
+/// failed resolution should be retried immediately or backed off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreState {
+    /// Never resolved yet.
+    Untested,
+    /// Last resolution succeeded.
+    Good,
+    /// Last resolution failed for any other reason: PD returned an error
+    /// (e.g. a timeout), or the address did not resolve to a socket address.
+    Timeout,
+    /// PD returned a store with an empty address, or a tombstoned store.
+    ProtocolViolation,
+}
+
+impl Display for StoreState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match *self {
+            StoreState::Untested => "untested",
+            StoreState::Good => "good",
+            StoreState::Timeout => "timeout",
+            StoreState::ProtocolViolation => "protocol_violation",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Controls how the multiple `SocketAddr`s a single store address can
+/// resolve to (e.g. a hostname with both an IPv4 and an IPv6 record) are
+/// ordered. `Runner` applies this once, right after resolution; it does
+/// not affect the main-address-before-status-address ordering, which
+/// this kvproto doesn't support (see `get_address`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressOrder {
+    Ipv4First,
+    Ipv6First,
+}
+
+impl Default for AddressOrder {
+    fn default() -> AddressOrder {
+        AddressOrder::Ipv4First
     }
 }
 
+/// A store's resolved addresses: every IP its single store address resolves
+/// to, ordered by `AddressOrder` (IPv4 ahead of IPv6 by default). `resolve`
+/// hands back `socks[0]` while it is healthy, and falls back to the next
+/// candidate once PD or DNS resolution for this store has recently failed,
+/// so a single unresolvable endpoint does not have to wait out the full
+/// refresh window. This does not observe gRPC-level connectivity, so a
+/// primary that is routable but not actually serving still wins until the
+/// next failed resolution.
 struct StoreAddr {
-    sock: SocketAddr,
+    socks: Vec<SocketAddr>,
     last_update: Instant,
+    state: StoreState,
+    failed_attempts: u32,
+    next_retry: Instant,
+}
+
+impl StoreAddr {
+    fn new(socks: Vec<SocketAddr>) -> StoreAddr {
+        let now = Instant::now();
+        StoreAddr {
+            socks: socks,
+            last_update: now,
+            state: StoreState::Untested,
+            failed_attempts: 0,
+            next_retry: now,
+        }
+    }
+
+    /// Returns the current health state of this cached address, used to
+    /// label the `RESOLVE_STORE_COUNTER` metric.
+    fn state(&self) -> StoreState {
+        self.state
+    }
+
+    /// The address `resolve` should currently hand back while healthy:
+    /// always the primary candidate. While backing off after a failure,
+    /// this instead returns the next candidate in preference order *only*
+    /// if one exists (the store address resolved to more than one IP,
+    /// e.g. both an IPv4 and an IPv6 record) — `None` otherwise, so the
+    /// caller falls through to the negative-cache error rather than
+    /// quietly re-serving the one address already known to be bad.
+    fn preferred(&self) -> Option<SocketAddr> {
+        if self.state == StoreState::Good {
+            self.socks.get(0).cloned()
+        } else {
+            self.socks.get(1).cloned()
+        }
+    }
+
+    fn mark_good(&mut self, socks: Vec<SocketAddr>) {
+        self.socks = socks;
+        self.last_update = Instant::now();
+        self.state = StoreState::Good;
+        self.failed_attempts = 0;
+        self.next_retry = self.last_update;
+    }
+
+    fn mark_failed(&mut self, state: StoreState) {
+        self.state = state;
+        self.failed_attempts += 1;
+        // Clamp the exponent: `failed_attempts` grows without bound for a
+        // store that stays down, and shifting a u64 left by 64 or more
+        // bits panics in debug builds. 16 already saturates well past
+        // RESOLVE_BACKOFF_CAP_MILLIS, so the clamp never changes the
+        // resulting backoff.
+        let exponent = self.failed_attempts.min(16);
+        let backoff = RESOLVE_BACKOFF_BASE_MILLIS.saturating_mul(1u64 << exponent)
+            .min(RESOLVE_BACKOFF_CAP_MILLIS);
+        self.next_retry = Instant::now() + Duration::from_millis(backoff);
+    }
 }
 
 pub struct Runner<T: PdClient> {
     pd_client: Arc<T>,
     store_addrs: HashMap<u64, StoreAddr>,
+    resolve_window_start: Instant,
+    resolve_window_count: u32,
+    address_order: AddressOrder,
 }
 
 impl<T: PdClient> Runner<T> {
+    /// Handles a `resolve` request coming off the task queue: serves it
+    /// from cache when possible, and otherwise enforces
+    /// `MAX_RESOLVES_PER_SEC` before issuing a fresh `get_store` call.
+    ///
+    /// There is no separate "currently resolving" bookkeeping here: the
+    /// worker processes one `Task` at a time and `resolve` runs to
+    /// completion (including the `get_store` call) before the next queued
+    /// task is dequeued, so by the time a second `resolve` for the same
+    /// store is handled, the first has already updated the cache above —
+    /// duplicate PD calls for concurrently-queued requests are avoided by
+    /// that cache check, not by tracking in-flight requests separately.
+    fn handle_resolve(&mut self, store_id: u64, cb: Callback) {
+        if let Some(s) = self.store_addrs.get(&store_id) {
+            let now = Instant::now();
+            let backing_off = s.state != StoreState::Good && now < s.next_retry;
+            let fresh = s.state == StoreState::Good &&
+                        now.duration_since(s.last_update).as_secs() < STORE_ADDRESS_REFRESH_SECONDS;
+            if backing_off {
+                if let Some(sock) = s.preferred() {
+                    cb.call_box((Ok(sock),));
+                } else {
+                    let attempts = s.failed_attempts;
+                    RESOLVE_STORE_COUNTER.with_label_values(&[&s.state().to_string()]).inc();
+                    cb.call_box((Err(box_err!("store {} address resolve is backing off after {} \
+                                                failed attempt(s)",
+                                               store_id,
+                                               attempts)),));
+                }
+                return;
+            }
+            if fresh {
+                if let Some(sock) = s.preferred() {
+                    cb.call_box((Ok(sock),));
+                    return;
+                }
+            }
+        }
+
+        if !self.take_resolve_budget() {
+            // Smooth the burst across windows instead of dropping the
+            // request outright, the same way `refresh` simply leaves
+            // stale entries for its next tick rather than erroring them:
+            // wait out the rest of the current window, then resolve with
+            // a fresh budget. The worker handles one task at a time
+            // anyway, so this only holds up tasks that are already queued
+            // behind this one, not concurrent callers.
+            RESOLVE_STORE_COUNTER.with_label_values(&["rate_limited"]).inc();
+            let elapsed = Instant::now().duration_since(self.resolve_window_start);
+            let wait = Duration::from_secs(1).checked_sub(elapsed).unwrap_or_default();
+            thread::sleep(wait);
+            self.resolve_window_start = Instant::now();
+            self.resolve_window_count = 0;
+            self.take_resolve_budget();
+        }
+
+        let resp = self.resolve(store_id);
+        cb.call_box((resp,))
+    }
+
+    /// Returns `true` and consumes one slot of the per-second resolve
+    /// budget, or `false` if the current window is already exhausted.
+    fn take_resolve_budget(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.resolve_window_start) >= Duration::from_secs(1) {
+            self.resolve_window_start = now;
+            self.resolve_window_count = 0;
+        }
+        if self.resolve_window_count >= MAX_RESOLVES_PER_SEC {
+            return false;
+        }
+        self.resolve_window_count += 1;
+        true
+    }
+
     fn resolve(&mut self, store_id: u64) -> Result<SocketAddr> {
         if let Some(s) = self.store_addrs.get(&store_id) {
             let now = Instant::now();
+            if s.state != StoreState::Good && now < s.next_retry {
+                if let Some(sock) = s.preferred() {
+                    return Ok(sock);
+                }
+                RESOLVE_STORE_COUNTER.with_label_values(&[&s.state().to_string()]).inc();
+                return Err(box_err!("store {} address resolve is backing off after {} \
+                                      failed attempt(s)",
+                                     store_id,
+                                     s.failed_attempts));
+            }
             let elasped = now.duration_since(s.last_update);
-            if elasped.as_secs() < STORE_ADDRESS_REFRESH_SECONDS {
-                return Ok(s.sock);
+            if s.state == StoreState::Good && elasped.as_secs() < STORE_ADDRESS_REFRESH_SECONDS {
+                if let Some(sock) = s.preferred() {
+                    return Ok(sock);
+                }
             }
         }
 
-        let addr = try!(self.get_address(store_id));
-        let sock = try!(util::to_socket_addr(addr.as_str()));
+        self.resolve_and_update(store_id)
+    }
 
-        let cache = StoreAddr {
-            sock: sock,
-            last_update: Instant::now(),
-        };
-        self.store_addrs.insert(store_id, cache);
+    /// Re-resolves every cached entry that is approaching expiry, so that
+    /// a subsequent `resolve` call can be served from a warm cache instead
+    /// of blocking on PD. Entries that are still well within their TTL, or
+    /// that are already backing off after a failure, are left alone.
+    fn refresh(&mut self) {
+        let now = Instant::now();
+        let refresh_ahead = Duration::from_secs(STORE_ADDRESS_REFRESH_SECONDS / 2);
+        let stale: Vec<u64> = self.store_addrs
+            .iter()
+            .filter(|&(_, s)| {
+                s.state == StoreState::Good && now.duration_since(s.last_update) >= refresh_ahead
+            })
+            .map(|(store_id, _)| *store_id)
+            .collect();
+
+        for store_id in stale {
+            // Respect the same MAX_RESOLVES_PER_SEC budget as foreground
+            // resolves, so a refresh tick over many stale entries can't
+            // flood PD; remaining entries are simply picked up on the
+            // next tick.
+            if !self.take_resolve_budget() {
+                RESOLVE_STORE_COUNTER.with_label_values(&["rate_limited"]).inc();
+                break;
+            }
+            // Errors are not returned to anyone here: the failure is
+            // recorded on the entry, so until its backoff expires `resolve`
+            // serves the fallback candidate if there is one and the backoff
+            // error otherwise, rather than the old primary address.
+            let _ = self.resolve_and_update(store_id);
+        }
+    }
 
-        Ok(sock)
+    fn resolve_and_update(&mut self, store_id: u64) -> Result<SocketAddr> {
+        // The single store address may itself resolve to more than one IP
+        // (e.g. a hostname with both an IPv4 and an IPv6 record); keep every
+        // candidate rather than just the first. A failure to resolve it is
+        // recorded on the entry just like a PD failure.
+        match self.get_address(store_id).and_then(|addr| resolve_all_socket_addrs(&addr)) {
+            Ok(mut socks) => {
+                // Order candidates per `self.address_order`, within the
+                // otherwise-preserved address preference order.
+                socks.sort_by_key(|s| match self.address_order {
+                    AddressOrder::Ipv4First => !s.is_ipv4(),
+                    AddressOrder::Ipv6First => s.is_ipv4(),
+                });
+                let preferred = socks[0];
+                self.store_addrs
+                    .entry(store_id)
+                    .or_insert_with(|| StoreAddr::new(socks.clone()))
+                    .mark_good(socks);
+                Ok(preferred)
+            }
+            Err(e) => {
+                let msg = format!("{}", e);
+                let state = if msg.contains("invalid empty address") ||
+                               msg.contains("has been removed") {
+                    StoreState::ProtocolViolation
+                } else {
+                    StoreState::Timeout
+                };
+                self.store_addrs
+                    .entry(store_id)
+                    .or_insert_with(|| StoreAddr::new(vec![]))
+                    .mark_failed(state);
+                RESOLVE_STORE_COUNTER.with_label_values(&[&state.to_string()]).inc();
+                Err(e)
+            }
+        }
     }
 
+    /// Returns the store's address. Candidate diversity for `preferred`'s
+    /// fallback comes from `resolve_all_socket_addrs` resolving the single
+    /// address below to every IP it has (e.g. a hostname with both an IPv4
+    /// and an IPv6 record), not from a second, separate store address:
+    /// this kvproto's `metapb::Store` doesn't expose one.
     fn get_address(&mut self, store_id: u64) -> Result<String> {
         let pd_client = self.pd_client.clone();
         let s = box_try!(pd_client.get_store(store_id));
@@ -99,36 +399,101 @@ impl<T: PdClient> Runner<T> {
     }
 }
 
+/// Resolves every `SocketAddr` a host:port string yields (e.g. a hostname
+/// with both an IPv4 and an IPv6 record), preserving the order in which
+/// the system resolver returns them.
+fn resolve_all_socket_addrs(addr: &str) -> Result<Vec<SocketAddr>> {
+    let socks: Vec<SocketAddr> = box_try!(addr.to_socket_addrs()).collect();
+    if socks.is_empty() {
+        return Err(box_err!("no address resolved for {}", addr));
+    }
+    Ok(socks)
+}
+
 impl<T: PdClient> Runnable<Task> for Runner<T> {
     fn run(&mut self, task: Task) {
-        let store_id = task.store_id;
-        let resp = self.resolve(store_id);
-        task.cb.call_box((resp,))
+        match task {
+            Task::Resolve { store_id, cb } => self.handle_resolve(store_id, cb),
+            Task::Refresh => self.refresh(),
+        }
     }
 }
 
 pub struct PdStoreAddrResolver {
     worker: Worker<Task>,
+    refresh_stop: Arc<AtomicBool>,
+    refresh_thread: Option<JoinHandle<()>>,
+}
+
+/// Spawns the ticker that periodically pushes a `Task::Refresh` onto the
+/// worker, driving the proactive background refresh.
+fn start_refresh_ticker(scheduler: Scheduler<Task>, stop: Arc<AtomicBool>) -> JoinHandle<()> {
+    let tick = Duration::from_secs(STORE_ADDRESS_REFRESH_SECONDS / 2);
+    let poll = Duration::from_millis(REFRESH_TICKER_POLL_MILLIS);
+    thread::Builder::new()
+        .name("store address refresh ticker".to_owned())
+        .spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                // Sleep in short slices rather than one long `tick` sleep
+                // so shutdown is observed promptly instead of blocking
+                // `Drop` for up to `tick`.
+                let mut waited = Duration::from_secs(0);
+                while waited < tick {
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let slice = cmp::min(poll, tick - waited);
+                    thread::sleep(slice);
+                    waited += slice;
+                }
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = scheduler.schedule(Task::Refresh) {
+                    error!("failed to schedule store address refresh: {:?}", e);
+                }
+            }
+        })
+        .unwrap()
 }
 
 impl PdStoreAddrResolver {
     pub fn new<T>(pd_client: Arc<T>) -> Result<PdStoreAddrResolver>
         where T: PdClient + 'static
     {
-        let mut r = PdStoreAddrResolver { worker: Worker::new("store address resolve worker") };
+        PdStoreAddrResolver::with_address_order(pd_client, AddressOrder::default())
+    }
+
+    /// Like `new`, but lets the caller override the default IPv4-before-
+    /// IPv6 preference applied when a store address resolves to more than
+    /// one candidate.
+    pub fn with_address_order<T>(pd_client: Arc<T>,
+                                  address_order: AddressOrder)
+                                  -> Result<PdStoreAddrResolver>
+        where T: PdClient + 'static
+    {
+        let mut r = PdStoreAddrResolver {
+            worker: Worker::new("store address resolve worker"),
+            refresh_stop: Arc::new(AtomicBool::new(false)),
+            refresh_thread: None,
+        };
 
         let runner = Runner {
             pd_client: pd_client,
             store_addrs: HashMap::default(),
+            resolve_window_start: Instant::now(),
+            resolve_window_count: 0,
+            address_order: address_order,
         };
         box_try!(r.worker.start(runner));
+        r.refresh_thread = Some(start_refresh_ticker(r.worker.scheduler(), r.refresh_stop.clone()));
         Ok(r)
     }
-}
 
-impl StoreAddrResolver for PdStoreAddrResolver {
-    fn resolve(&self, store_id: u64, cb: Callback) -> Result<()> {
-        let task = Task {
+    /// Schedules a resolve task onto the worker; both the callback and
+    /// future flavors of the public API go through this one primitive.
+    fn schedule_resolve(&self, store_id: u64, cb: Callback) -> Result<()> {
+        let task = Task::Resolve {
             store_id: store_id,
             cb: cb,
         };
@@ -137,8 +502,47 @@ impl StoreAddrResolver for PdStoreAddrResolver {
     }
 }
 
+impl StoreAddrResolver for PdStoreAddrResolver {
+    // `Runner::resolve` only has a synchronous `PdClient::get_store` to
+    // work with, so there is no reactor to consolidate onto here: both
+    // flavors of this API are equally-thin callers of `schedule_resolve`,
+    // rather than one being built as a wrapper on top of the other.
+    fn resolve(&self, store_id: u64, cb: Callback) -> Result<()> {
+        self.schedule_resolve(store_id, cb)
+    }
+
+    fn resolve_future(&self, store_id: u64) -> PdFuture<SocketAddr> {
+        let (tx, rx) = oneshot::channel();
+        let res = self.schedule_resolve(store_id,
+                                         Box::new(move |r| {
+                                             // The receiving end may already be gone if
+                                             // the future was dropped; not our problem.
+                                             let _ = tx.send(r);
+                                         }));
+        if let Err(e) = res {
+            let err = box_err!("failed to schedule resolve task for store {}: {:?}",
+                                store_id,
+                                e);
+            return Box::new(futures::future::result(Err(err)));
+        }
+        Box::new(rx.then(move |r| match r {
+            // `tx.send` carries a `super::Result<SocketAddr>`; re-wrap its
+            // error explicitly so this doesn't silently depend on
+            // `super::Error` and `PdFuture`'s error type happening to
+            // coincide.
+            Ok(Ok(sock)) => Ok(sock),
+            Ok(Err(e)) => Err(box_err!("{}", e)),
+            Err(_) => Err(box_err!("resolve callback for store {} was dropped", store_id)),
+        }))
+    }
+}
+
 impl Drop for PdStoreAddrResolver {
     fn drop(&mut self) {
+        self.refresh_stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.refresh_thread.take() {
+            let _ = t.join();
+        }
         if let Some(Err(e)) = self.worker.stop().map(|h| h.join()) {
             error!("failed to stop store address resolve thread: {:?}!!!", e);
         }
@@ -149,6 +553,7 @@ impl Drop for PdStoreAddrResolver {
 mod tests {
     use super::*;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::{Instant, Duration};
     use std::ops::Sub;
     use std::net::SocketAddr;
@@ -166,6 +571,9 @@ mod tests {
     struct MockPdClient {
         start: Instant,
         store: metapb::Store,
+        // Counts `get_store` calls, so tests can assert that a cached or
+        // backing-off resolution didn't reach out to "PD" at all.
+        calls: AtomicUsize,
     }
 
     impl PdClient for MockPdClient {
@@ -185,6 +593,7 @@ mod tests {
             unimplemented!();
         }
         fn get_store(&self, _: u64) -> Result<metapb::Store> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
             // The store address will be changed every millisecond.
             let mut store = self.store.clone();
             let mut sock = SocketAddr::from_str(store.get_address()).unwrap();
@@ -231,10 +640,14 @@ mod tests {
         let client = MockPdClient {
             start: Instant::now(),
             store: store,
+            calls: AtomicUsize::new(0),
         };
         Runner {
             pd_client: Arc::new(client),
             store_addrs: HashMap::default(),
+            resolve_window_start: Instant::now(),
+            resolve_window_count: 0,
+            address_order: AddressOrder::default(),
         }
     }
 
@@ -295,4 +708,79 @@ mod tests {
         let sock = runner.resolve(store_id).unwrap();
         assert_eq!(sock.port(), port);
     }
+
+    #[test]
+    fn test_resolve_backoff_returns_cached_error_without_hitting_pd() {
+        let store = new_store(STORE_ADDR, metapb::StoreState::Up);
+        let store_id = store.get_id();
+        let mut runner = new_runner(store);
+
+        let mut addr = StoreAddr::new(vec![SocketAddr::from_str(STORE_ADDR).unwrap()]);
+        addr.mark_failed(StoreState::Timeout);
+        runner.store_addrs.insert(store_id, addr);
+
+        let calls_before = runner.pd_client.calls.load(Ordering::SeqCst);
+        assert!(runner.resolve(store_id).is_err());
+        assert_eq!(runner.pd_client.calls.load(Ordering::SeqCst), calls_before);
+    }
+
+    #[test]
+    fn test_preferred_fallback_on_backoff() {
+        let store = new_store(STORE_ADDR, metapb::StoreState::Up);
+        let store_id = store.get_id();
+        let mut runner = new_runner(store);
+
+        let primary = SocketAddr::from_str(STORE_ADDR).unwrap();
+        let fallback = SocketAddr::from_str("127.0.0.1:23456").unwrap();
+        let mut addr = StoreAddr::new(vec![primary, fallback]);
+        addr.mark_failed(StoreState::Timeout);
+        runner.store_addrs.insert(store_id, addr);
+
+        assert_eq!(runner.resolve(store_id).unwrap(), fallback);
+    }
+
+    #[test]
+    fn test_resolve_rate_limit_exhausted() {
+        let store = new_store(STORE_ADDR, metapb::StoreState::Up);
+        let mut runner = new_runner(store);
+
+        for _ in 0..MAX_RESOLVES_PER_SEC {
+            assert!(runner.take_resolve_budget());
+        }
+        assert!(!runner.take_resolve_budget());
+    }
+
+    #[test]
+    fn test_refresh_warms_cache() {
+        let store = new_store(STORE_ADDR, metapb::StoreState::Up);
+        let store_id = store.get_id();
+        let mut runner = new_runner(store);
+
+        let sock = runner.resolve(store_id).unwrap();
+        let port = sock.port();
+
+        thread::sleep(Duration::from_millis(2));
+        {
+            let mut s = runner.store_addrs.get_mut(&store_id).unwrap();
+            let now = Instant::now();
+            s.last_update = now.sub(Duration::from_secs(STORE_ADDRESS_REFRESH_SECONDS));
+        }
+        runner.refresh();
+
+        let s = runner.store_addrs.get(&store_id).unwrap();
+        assert_ne!(s.preferred().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_resolve_future_returns_resolved_address() {
+        let store = new_store(STORE_ADDR, metapb::StoreState::Up);
+        let client = MockPdClient {
+            start: Instant::now(),
+            store: store,
+            calls: AtomicUsize::new(0),
+        };
+        let resolver = PdStoreAddrResolver::new(Arc::new(client)).unwrap();
+        let sock = resolver.resolve_future(1).wait().unwrap();
+        assert_eq!(sock.ip().to_string(), "127.0.0.1");
+    }
 }